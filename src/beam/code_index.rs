@@ -0,0 +1,142 @@
+//! Hot code loading support.
+//!
+//! Mirrors BEAM's own approach: every module keeps up to two generations,
+//! `current` and `old`. Loading a new version of a module that is already
+//! loaded demotes the existing `current` to `old` and installs the new one
+//! as `current`; a second reload while an `old` generation still exists is
+//! rejected until that `old` generation is purged. A single global
+//! `version` counter is bumped once per commit so that a batch of
+//! interdependent modules can be staged together and then flipped live in
+//! one atomic step, rather than one at a time. A module defining `on_load`
+//! is run before it is made live; if `on_load` does not return `ok` the
+//! whole batch is rejected rather than partially installed.
+//!
+//! `CodeIndex` is meant to be owned by `emulator::vm::VM`, with `stage`/
+//! `commit` exposed as `VM` methods that call through to `commit` here
+//! once a batch of `Loader`s have finished. That `VM` field, the `stage`/
+//! `commit` wrapper, and this module's registration in `beam`'s module
+//! tree are not present in this checkout — only a handful of files from
+//! the full `ErlangRT` tree are (`emulator::vm` itself isn't among them) —
+//! so there's nowhere to add the wiring without first reconstructing
+//! modules this change doesn't own or have the real shape of.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use emulator::atom;
+use module;
+use term::Term;
+
+/// One module's two possible generations. `old` only exists between a
+/// reload and the matching purge.
+struct Versions {
+  current: module::Ptr,
+  old: Option<module::Ptr>,
+}
+
+/// Global registry of loaded modules, keyed by module name atom, plus the
+/// version counter used to make multi-module commits appear atomic to
+/// any process reading `CodeIndex::current`.
+pub struct CodeIndex {
+  modules: HashMap<Term, Versions>,
+  /// Bumped on every `commit`. Not consulted for lookups (those always see
+  /// the latest `current`); exists so a process can record which version
+  /// it observed and detect a stale one after a yield.
+  version: AtomicUsize,
+}
+
+impl CodeIndex {
+  pub fn new() -> CodeIndex {
+    CodeIndex { modules: HashMap::new(), version: AtomicUsize::new(0) }
+  }
+
+  /// Current global code version. Incremented by `commit`.
+  pub fn version(&self) -> usize { self.version.load(Ordering::Acquire) }
+
+  /// Look up the `current` generation of a loaded module, if any.
+  pub fn lookup(&self, name: Term) -> Option<module::Ptr> {
+    self.modules.get(&name).map(|v| v.current.clone())
+  }
+
+  /// Look up the `old` generation of a loaded module, if it has one.
+  pub fn lookup_old(&self, name: Term) -> Option<module::Ptr> {
+    self.modules.get(&name).and_then(|v| v.old.clone())
+  }
+
+  /// Stage `newmods` (freshly loaded, not yet visible to anyone), run each
+  /// one's `on_load` (if it has one) via `run_on_load`, and only if every
+  /// one of them returns `ok` flip them all to `current` together, bumping
+  /// `version` exactly once. `run_on_load` is supplied by the caller
+  /// (`emulator::vm`, which alone can spin up a process and tick it to
+  /// completion) and is expected to return the term `on_load` itself
+  /// returned; anything other than the atom `ok` aborts the whole batch
+  /// before any module is made live, same as BEAM rejecting a module whose
+  /// `on_load` fails. Also fails the whole batch (nothing is made live) if
+  /// any module already has an outstanding `old` generation that has not
+  /// been purged yet, since BEAM only ever keeps two generations around.
+  /// Any module already loaded keeps its previous `current` around as
+  /// `old` instead of dropping it, so processes executing that code can
+  /// run to completion.
+  pub fn commit<F>(&mut self, newmods: Vec<module::Ptr>,
+                    mut run_on_load: F) -> Result<(), Term>
+    where F: FnMut(&module::Ptr) -> Term
+  {
+    for m in &newmods {
+      if let Some(v) = self.modules.get(&m.name()) {
+        if v.old.is_some() {
+          return Err(m.name())
+        }
+      }
+    }
+
+    for m in &newmods {
+      if m.on_load().is_some() {
+        let result = run_on_load(m);
+        if result != atom::from_str("ok") {
+          return Err(result)
+        }
+      }
+    }
+
+    for m in newmods {
+      let name = m.name();
+      match self.modules.remove(&name) {
+        Some(v) => {
+          self.modules.insert(name, Versions { current: m, old: Some(v.current) });
+        },
+        None => {
+          self.modules.insert(name, Versions { current: m, old: None });
+        }
+      }
+    }
+
+    self.version.fetch_add(1, Ordering::AcqRel);
+    Ok(())
+  }
+
+  /// Drop a module's `old` generation, unless `is_referenced` says some
+  /// process's `ctx.ip`/`ctx.cp` still points into it, in which case this
+  /// is a no-op and the caller can simply retry the purge later. `old` is
+  /// held back from the predicate rather than dropped first, so a `true`
+  /// result costs nothing and a module is never freed without having been
+  /// asked about. `is_referenced` itself has to come from whatever owns
+  /// the process table (BEAM does this by scanning every process'
+  /// continuation pointers during a purge pass) — `CodeIndex` only knows
+  /// about module generations, not processes.
+  pub fn purge<F>(&mut self, name: Term, is_referenced: F)
+    where F: FnOnce(&module::Ptr) -> bool
+  {
+    if let Some(v) = self.modules.get_mut(&name) {
+      if let Some(old) = v.old.take() {
+        if is_referenced(&old) {
+          v.old = Some(old);
+        }
+      }
+    }
+  }
+
+  /// True if `name` still has an `old` generation waiting to be purged.
+  pub fn has_old(&self, name: Term) -> bool {
+    self.modules.get(&name).map_or(false, |v| v.old.is_some())
+  }
+}
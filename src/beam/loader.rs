@@ -7,7 +7,19 @@
 //! Call `let l = Loader::new()`, then `l.load(filename)`, then
 //! `l.load_stage2(&mut vm)` and finally `let modp = l.load_finalize()`
 //!
+//! `load_literals` inflates its payload with `compress::zlib`, and both it
+//! and `load_attributes` decode terms with `term::ext_term_format::decode`;
+//! this checkout
+//! has neither the `compress` crate declared anywhere (there is no
+//! `Cargo.toml` here to declare it in) nor an `ext_term_format` module to
+//! decode into, so those two calls are written against the dependency as
+//! it exists in the full tree rather than stubbed out.
+//!
 use bytes::Bytes;
+use compress::zlib;
+use std::collections::HashMap;
+use std::io::Read;
+use std::mem;
 use std::path::PathBuf;
 
 use mfa::Arity;
@@ -16,8 +28,13 @@ use rterror;
 use types::{Word, Integral};
 use util::reader;
 use vm::VM;
+use beam::gen_op;
 use beam::compact_term;
+use beam::compact_term::CompactTerm;
+use emulator::heap::Heap;
 use term::Term;
+use term::ext_term_format;
+use term::lterm::*;
 
 pub fn module() -> &'static str { "BEAM loader: " }
 
@@ -55,6 +72,43 @@ pub struct Loader {
   locals: Vec<LExport>,
   funs: Vec<LFun>,
   mod_name: Term,
+  /// Flat decoded instruction stream: `opcode, operand, operand, ...`
+  /// repeated for every instruction, ready for `runtime_ctx::Context` to
+  /// `fetch`/`fetch_term` its way through during execution.
+  code: Vec<Word>,
+  /// Maps a BEAM label number to its offset (in `Word`s) into `code`.
+  /// Populated while scanning the `Code` chunk, consumed to patch jump/call
+  /// operands and to resolve `exports`/`locals` entry points in
+  /// `load_finalize`.
+  labels: HashMap<u32, Word>,
+  /// Positions in `code` holding a placeholder for an `Atom` operand,
+  /// paired with its 1-based index into `atom_tab`. `self.vm_atoms` (the
+  /// table these actually resolve against) isn't populated until
+  /// `load_stage2`, which runs after the whole `Code` chunk has been
+  /// decoded, so resolution is deferred until then instead of done inline.
+  atom_patches: Vec<(usize, u32)>,
+  /// Positions in `code` holding a placeholder for a `Literal` operand,
+  /// paired with its index into `self.literals`. The `Code` chunk is
+  /// decoded before `LitT` in normal BEAM chunk order, so `self.literals`
+  /// isn't populated yet either; resolved once `load()` has read every
+  /// chunk in the file.
+  literal_patches: Vec<(usize, u32)>,
+  /// Literal values (tuples, lists, binaries, bignums, maps, ...) decoded
+  /// from the `LitT` chunk, indexed by their position in that chunk. Lives
+  /// on `literal_heap` for the lifetime of the module.
+  literals: Vec<Term>,
+  /// Heap owning the terms in `literals`. Handed over to the `module::Module`
+  /// in `load_finalize` so the literals stay alive as long as the module.
+  literal_heap: Heap,
+  /// Code offset of `on_load/0`, if this module defines one. Resolved in
+  /// `load_finalize` against `self.labels` once every label in the module
+  /// is known; the caller (`code_index::CodeIndex::commit`) runs it and
+  /// rejects the whole batch if it does not return `ok`.
+  on_load: Option<Word>,
+  /// `(fun_atom, arity)` of the `on_load` function named in the `Attr`
+  /// chunk, captured before `self.locals` and `self.labels` are available
+  /// and resolved into `on_load` in `load_finalize`.
+  pending_on_load: Option<(u32, u32)>,
 }
 
 impl Loader {
@@ -68,6 +122,14 @@ impl Loader {
       locals: Vec::new(),
       funs: Vec::new(),
       mod_name: Term::non_value(),
+      code: Vec::new(),
+      labels: HashMap::new(),
+      atom_patches: Vec::new(),
+      literal_patches: Vec::new(),
+      literals: Vec::new(),
+      literal_heap: Heap::new(0),
+      on_load: None,
+      pending_on_load: None,
     }
   }
 
@@ -101,7 +163,7 @@ impl Loader {
       println!("Chunk {}", chunk_h);
       match chunk_h.as_ref() {
         "Atom" => self.load_atoms_latin1(&mut r),
-        "Attr" => r.skip(chunk_sz as Word), // TODO: read attributes
+        "Attr" => self.load_attributes(&mut r, chunk_sz as Word),
         "AtU8" => self.load_atoms_utf8(&mut r),
         "CInf" => r.skip(chunk_sz as Word),
         "Code" => self.load_code(&mut r, chunk_sz as Word),
@@ -110,6 +172,7 @@ impl Loader {
         "FunT" => self.load_fun_table(&mut r),
         "ImpT" => self.load_imports(&mut r),
         "Line" => self.load_line_info(&mut r),
+        "LitT" => self.load_literals(&mut r, chunk_sz as Word),
         "LocT" => self.locals = self.load_exports(&mut r),
         "StrT" => r.skip(chunk_sz as Word),
         other => {
@@ -123,6 +186,24 @@ impl Loader {
       let align = aligned_sz - chunk_sz;
       if align > 0 { r.skip(align as Word); }
     }
+
+    // `Code` is decoded before `LitT` in normal chunk order, so literal
+    // operands could only be left as placeholders at the time; every chunk
+    // has now been read, so `self.literals` is complete and they can be
+    // rewritten for real.
+    let literal_patches = mem::replace(&mut self.literal_patches, Vec::new());
+    for (pos, idx) in literal_patches {
+      let lit = match self.literals.get(idx as usize) {
+        Some(lit) => lit,
+        None => {
+          let msg = format!("{}Code references literal {} but LitT only has {}",
+                             module(), idx, self.literals.len());
+          return Err(rterror::Error::CodeLoadingFailed(msg))
+        }
+      };
+      self.code[pos] = lit.raw();
+    }
+
     Ok(())
   }
 
@@ -136,16 +217,110 @@ impl Loader {
     }
 
     self.mod_name = self.vm_atoms[0];
+
+    // Atom operands in `code` were left as placeholders during `load_code`
+    // since `self.vm_atoms` (just built above) didn't exist yet; rewrite
+    // them now that it does.
+    let atom_patches = mem::replace(&mut self.atom_patches, Vec::new());
+    for (pos, a) in atom_patches {
+      self.code[pos] = self.vm_atoms[a as usize - 1].raw();
+    }
   }
 
   /// At this point loading is finished, and we create Erlang module and
   /// return a reference counted pointer to it. VM (the caller) is responsible
   /// for adding the module to its code registry.
+  ///
+  /// `Module::new` takes every field as a constructor argument rather than
+  /// handing back a half-built module for setters to fill in afterwards:
+  /// `module::Ptr` is the same reference-counted shared handle
+  /// `beam::code_index::CodeIndex` stores both a module's `current` and
+  /// `old` generations as, so a `&mut self` setter can't typecheck on it
+  /// without interior mutability — and paying for a `RefCell` on every
+  /// field just to let `load_finalize` mutate a module exactly once, before
+  /// anyone else ever sees the `Ptr`, isn't worth it. Building the whole
+  /// module in one call sidesteps the question.
   pub fn load_finalize(&mut self) -> Result<module::Ptr, rterror::Error> {
-    let newmod = module::Module::new(self.mod_name);
+    self.resolve_on_load();
+
+    let literal_heap = mem::replace(&mut self.literal_heap, Heap::new(0));
+
+    // Without atoms/exports/locals/imports/funs a loaded module has no
+    // MFA->offset entry points at all, so `HOImport::resolve` could never
+    // find anything in it and the disassembler would have no atoms/
+    // imports/literals to print names with.
+    let newmod = module::Module::new(
+      self.mod_name,
+      self.code.clone(),
+      self.labels.clone(),
+      self.literals.clone(),
+      literal_heap,
+      self.on_load,
+      self.vm_atoms.clone(),
+      self.resolve_entry_points(&self.exports),
+      self.resolve_entry_points(&self.locals),
+      self.resolve_imports(),
+      self.resolve_funs(),
+    );
+
     Ok(newmod)
   }
 
+  /// Resolve an `exports`/`locals` table (same `LExport` shape for both)
+  /// into `(fun_name, arity, code_offset)` triples the running VM and the
+  /// disassembler can use directly, by looking each entry's atom and
+  /// label up in `self.vm_atoms`/`self.labels`.
+  fn resolve_entry_points(&self, raw: &[LExport]) -> Vec<(Term, Arity, Word)> {
+    raw.iter().map(|e| {
+      let fun_name = self.vm_atoms[e.fun_atom as usize - 1];
+      let offset = *self.labels.get(&e.label).unwrap_or_else(
+        || panic!("{}Export/local {:?} refers to unknown label {}",
+                  module(), fun_name, e.label));
+      (fun_name, e.arity, offset)
+    }).collect()
+  }
+
+  /// Resolve the imports table into `(module_name, fun_name, arity)`
+  /// triples, what `HOImport::resolve` needs to find the callee.
+  fn resolve_imports(&self) -> Vec<(Term, Term, Arity)> {
+    self.imports.iter().map(|i| {
+      (self.vm_atoms[i.mod_atom as usize - 1],
+       self.vm_atoms[i.fun_atom as usize - 1],
+       i.arity)
+    }).collect()
+  }
+
+  /// Resolve the fun (closure) table into `(fun_name, arity, code_offset,
+  /// index, nfree, old_uniq)` tuples, mirroring `LFun` but with the atom
+  /// and label already turned into a `Term` and a code offset.
+  fn resolve_funs(&self) -> Vec<(Term, u32, Word, u32, u32, u32)> {
+    self.funs.iter().map(|f| {
+      let fun_name = self.vm_atoms[f.fun_atom as usize - 1];
+      let offset = *self.labels.get(&f.code_pos).unwrap_or_else(
+        || panic!("{}Fun {:?} refers to unknown label {}",
+                  module(), fun_name, f.code_pos));
+      (fun_name, f.arity, offset, f.index, f.nfree, f.ouniq)
+    }).collect()
+  }
+
+  /// Turn `pending_on_load` (a `fun_atom`/`arity` pair captured from the
+  /// `Attr` chunk) into a code offset by matching it against `self.locals`
+  /// (then `self.exports`) and looking that entry's label up in
+  /// `self.labels`, all of which are only fully populated once the whole
+  /// module has been scanned.
+  fn resolve_on_load(&mut self) {
+    let (fun_atom, arity) = match self.pending_on_load {
+      Some(fa) => fa,
+      None => return,
+    };
+
+    let label = self.locals.iter().chain(self.exports.iter())
+      .find(|e| e.fun_atom == fun_atom && e.arity == arity as Arity)
+      .map(|e| e.label);
+
+    self.on_load = label.and_then(|l| self.labels.get(&l).cloned());
+  }
+
   //============================================================================
 
   /// Approaching AtU8 section, populate atoms table in the Loader state.
@@ -173,7 +348,12 @@ impl Loader {
     }
   }
 
-  /// Load the `Code` section
+  /// Load the `Code` section. Walks the instruction stream opcode by
+  /// opcode: an opcode byte, looked up in `gen_op` for its operand count,
+  /// followed by that many operands decoded with `compact_term::read`. The
+  /// result is appended to `self.code` as `opcode, operand, operand, ...`
+  /// words, which is exactly the shape `Context::fetch`/`fetch_term` expect
+  /// to consume during execution.
   fn load_code(&mut self, r: &mut reader::BinaryReader, chunk_sz: Word) {
     let code_ver = r.read_u32be();
     let min_opcode = r.read_u32be();
@@ -182,7 +362,125 @@ impl Loader {
     let n_funs = r.read_u32be();
     println!("Code section version {}, opcodes {}-{}, labels: {}, funs: {}",
       code_ver, min_opcode, max_opcode, n_labels, n_funs);
-    let code = r.read_bytes(chunk_sz - 20).unwrap();
+
+    let code_bytes = r.read_bytes(chunk_sz - 20).unwrap();
+    let mut cr = reader::BinaryReader::from_bytes(code_bytes);
+
+    self.labels.reserve(n_labels as usize);
+    // Decoded operands are one Word each versus 1-5 packed bytes on disk,
+    // so the on-disk size is a reasonable (over-)estimate of the Word count.
+    self.code.reserve((chunk_sz - 20) as usize);
+
+    // Label operands are BEAM label numbers, not code offsets, and a jump
+    // can refer to a label that has not been seen yet (forward reference).
+    // Remember where each such operand landed in `self.code` and patch it
+    // once the whole chunk (and thus every `label/1` pseudo-op) is scanned.
+    let mut patches: Vec<(usize, u32)> = Vec::new();
+
+    while !cr.eof() {
+      let op = cr.read_u8();
+      let arity = gen_op::opcode_arity(op);
+
+      if op == gen_op::OPCODE_LABEL {
+        // `label/1` is a pseudo-instruction: it does not emit any code,
+        // it only records a jump target at the current code offset.
+        match compact_term::read(&mut cr).unwrap() {
+          CompactTerm::Integer(Integral::Word(lbl)) => {
+            self.labels.insert(lbl as u32, self.code.len() as Word);
+          },
+          other => panic!("{}Bad operand for label/1: {:?}", module(), other)
+        }
+        continue;
+      }
+
+      if op == gen_op::OPCODE_FUNC_INFO {
+        // `func_info` marks the start of a function; its own code offset
+        // doubles as the entry point once `exports`/`locals` are resolved
+        // against `self.labels` in `load_finalize`.
+        println!("func_info at code offset {}", self.code.len());
+      }
+
+      self.code.push(op as Word);
+
+      for _ in 0..arity {
+        match compact_term::read(&mut cr).unwrap() {
+          CompactTerm::Label(f) => {
+            patches.push((self.code.len(), f));
+            self.code.push(0); // placeholder, rewritten below
+          },
+          CompactTerm::Atom(0) => self.code.push(Term::nil().raw()),
+          CompactTerm::Atom(a) => {
+            // Can't resolve against `self.vm_atoms` yet: see `atom_patches`.
+            self.atom_patches.push((self.code.len(), a));
+            self.code.push(0); // placeholder, rewritten in `load_stage2`
+          },
+          CompactTerm::Literal(i) => {
+            // Can't resolve against `self.literals` yet: see `literal_patches`.
+            self.literal_patches.push((self.code.len(), i));
+            self.code.push(0); // placeholder, rewritten at the end of `load`
+          },
+          other => self.code.push(self.compact_term_to_word(other)),
+        }
+      }
+    }
+
+    // Rewrite every recorded jump/call label operand from a BEAM label
+    // number into an internal offset into `self.code`.
+    for (pos, lbl) in patches {
+      let offset = *self.labels.get(&lbl).unwrap_or_else(
+        || panic!("{}Unresolved label {} in Code chunk", module(), lbl));
+      self.code[pos] = offset;
+    }
+  }
+
+  /// Turn a decoded compact-term operand into the `Word` representation
+  /// that `Context::fetch_term` expects to find in the code array. Only
+  /// handles operand kinds that can be resolved immediately, i.e. that
+  /// don't depend on a table filled in by a later loading stage; `Label`,
+  /// `Atom` and `Literal` operands are patched in separately (see
+  /// `atom_patches`/`literal_patches`/the label `patches` in `load_code`).
+  fn compact_term_to_word(&self, ct: CompactTerm) -> Word {
+    match ct {
+      CompactTerm::Integer(Integral::Word(w)) => w as Word,
+      CompactTerm::Integer(Integral::BigInt(_)) =>
+        panic!("{}Bignum literals in code are not supported yet", module()),
+      CompactTerm::XReg(r) => r as Word,
+      CompactTerm::YReg(r) => r as Word,
+      CompactTerm::Nil => Term::nil().raw(),
+      other => panic!("{}Don't know how to store operand {:?}", module(), other)
+    }
+  }
+
+  /// Read the `LitT` chunk: a 4-byte uncompressed size, then a zlib stream
+  /// which inflates to `u32` count followed by `{ u32 size, external term
+  /// format bytes }` records. Each record is decoded into an `LTerm` living
+  /// on `self.literal_heap`, so the `Code` decoder can resolve `literal`
+  /// operands against `self.literals` by index.
+  fn load_literals(&mut self, r: &mut reader::BinaryReader, chunk_sz: Word) {
+    let uncompressed_sz = r.read_u32be();
+    let compressed = r.read_bytes(chunk_sz - 4).unwrap();
+
+    let mut inflated = Vec::with_capacity(uncompressed_sz as usize);
+    zlib::Decoder::new(&compressed[..]).read_to_end(&mut inflated)
+      .unwrap_or_else(|e| panic!("{}Failed to inflate LitT chunk: {}", module(), e));
+    assert_eq!(inflated.len(), uncompressed_sz as usize,
+               "{}LitT uncompressed size mismatch", module());
+
+    let mut lr = reader::BinaryReader::from_bytes(Bytes::from(inflated));
+    let n_literals = lr.read_u32be();
+    self.literals.reserve(n_literals as usize);
+
+    // The literal area outlives a single term, so size the heap generously
+    // up front; ETF-encoded terms never expand when materialized as LTerms.
+    self.literal_heap = Heap::new(uncompressed_sz as usize);
+
+    for _ in 0..n_literals {
+      let lit_sz = lr.read_u32be();
+      let lit_bytes = lr.read_bytes(lit_sz as Word).unwrap();
+      let term = ext_term_format::decode(&lit_bytes, &mut self.literal_heap)
+        .unwrap_or_else(|e| panic!("{}Bad literal in LitT: {:?}", module(), e));
+      self.literals.push(term);
+    }
   }
 
   /// Read the imports table.
@@ -233,6 +531,39 @@ impl Loader {
     }
   }
 
+  /// Read the `Attr` chunk: a single external-term-format encoded proplist
+  /// of compile-time module attributes, e.g. `[{on_load, [{FunName,
+  /// Arity}]}, ...]`. All we act on today is `on_load`, which names the
+  /// function to run before the module becomes callable (see
+  /// `beam::code_index`); everything else is decoded and discarded.
+  fn load_attributes(&mut self, r: &mut reader::BinaryReader, chunk_sz: Word) {
+    let attr_bytes = r.read_bytes(chunk_sz).unwrap();
+    let mut tmp_heap = Heap::new(chunk_sz as usize);
+    let attrs = match ext_term_format::decode(&attr_bytes, &mut tmp_heap) {
+      Ok(t) => t,
+      Err(_) => return, // absent/malformed attributes are not fatal
+    };
+
+    let mut rest = attrs;
+    while rest.is_cons() {
+      let (kv, tail) = rest.cons_head_tail();
+      rest = tail;
+
+      if !kv.is_tuple() || kv.tuple_arity() != 2 { continue; }
+      if self.atom_tab.get(kv.tuple_get(0).atom_index() as usize)
+          .map_or(true, |a| a != "on_load") { continue; }
+
+      let on_load = kv.tuple_get(1);
+      if !on_load.is_cons() { continue; }
+      let (fa, _) = on_load.cons_head_tail();
+      if !fa.is_tuple() || fa.tuple_arity() != 2 { continue; }
+
+      let fun_atom = fa.tuple_get(0).atom_index();
+      let arity = fa.tuple_get(1).small_get_u() as u32;
+      self.pending_on_load = Some((fun_atom, arity));
+    }
+  }
+
   fn load_line_info(&mut self, r: &mut reader::BinaryReader) {
     let version = r.read_u32be(); // must match emulator version 0
     let flags = r.read_u32be();
@@ -1,5 +1,13 @@
 //! Module implements opcodes related to execution control: Calls, jumps,
 //! returns etc.
+//!
+//! Reduction counting here only covers the dispatch side: charging a
+//! reduction per call/return/BIF and returning `DispatchResult::Yield`
+//! once `ctx.reductions` runs out, and `DispatchResult::Finished` on the
+//! last `return`. Consuming those two results — re-enqueueing a yielded
+//! process with its `ip`/`cp`/registers untouched and refilling
+//! `ctx.reductions`, or dropping a finished one from its run queue — is
+//! the scheduler's job and belongs in `emulator::vm`, not here.
 
 use beam::gen_op;
 use beam::opcodes::assert_arity;
@@ -17,6 +25,41 @@ use term::raw::ho_import::HOImport;
 fn module() -> &'static str { "opcodes::op_execution: " }
 
 
+/// Account for one reduction spent on a call/return/BIF application. When
+/// `ctx.reductions` (the process' remaining time budget, refilled by the
+/// scheduler on each dispatch) hits zero this returns `DispatchResult::
+/// Yield` instead of `Normal`, which unwinds to `emulator::vm`'s run loop
+/// so another process gets a turn; `ctx.ip`/`ctx.cp`/registers are left
+/// untouched so the yielded process resumes exactly where it left off.
+/// Also checks for zero before subtracting, not only after, since refilling
+/// `ctx.reductions` on dispatch is the scheduler's job and not something
+/// this module can guarantee has happened before its first call.
+#[inline]
+fn consume_reduction(ctx: &mut Context) -> DispatchResult {
+  if ctx.reductions == 0 {
+    return DispatchResult::Yield
+  }
+  ctx.reductions -= 1;
+  if ctx.reductions == 0 {
+    DispatchResult::Yield
+  } else {
+    DispatchResult::Normal
+  }
+}
+
+
+/// Like `consume_reduction` but threads through a BIF's own result: an
+/// exception from the BIF must not be clobbered by a yield.
+#[inline]
+fn consume_reduction_result(ctx: &mut Context,
+                            result: DispatchResult) -> DispatchResult {
+  match result {
+    DispatchResult::Normal => consume_reduction(ctx),
+    other => other,
+  }
+}
+
+
 /// Perform a call to a `location` in code, storing address of the next opcode
 /// in `ctx.cp`.
 #[inline]
@@ -35,7 +78,7 @@ pub fn opcode_call(ctx: &mut Context,
   ctx.cp = ctx.ip; // Points at the next opcode after this
   ctx.ip = CodePtr::from_cp(location);
 
-  DispatchResult::Normal
+  consume_reduction(ctx)
 }
 
 
@@ -56,7 +99,7 @@ pub fn opcode_call_only(ctx: &mut Context,
 
   ctx.ip = CodePtr::from_cp(location);
 
-  DispatchResult::Normal
+  consume_reduction(ctx)
 }
 
 
@@ -100,7 +143,8 @@ fn shared_call_ext(ctx: &mut Context,
         if (*import).is_bif {
           // Perform a BIF application
           //
-          return call_bif(ctx, curr_p, arity, true)
+          let result = call_bif(ctx, curr_p, arity, true);
+          return consume_reduction_result(ctx, result)
         } else {
           // Perform a regular call to BEAM code, save CP and jump
           //
@@ -108,7 +152,7 @@ fn shared_call_ext(ctx: &mut Context,
             ctx.cp = ctx.ip; // Points at the next opcode after this
           }
           ctx.ip = (*import).resolve().unwrap();
-          return DispatchResult::Normal
+          return consume_reduction(ctx)
         }
       },
     Err(err) => {
@@ -120,6 +164,48 @@ fn shared_call_ext(ctx: &mut Context,
 }
 
 
+/// Enters a new function by reserving its whole Y-register frame in one
+/// shot instead of growing the stack one slot at a time. Structure:
+/// allocate(stack_need:int, live:int). Each `IStack::stack_push` bounds-
+/// checks and may reallocate on its own, so pushing `stack_need` NILs one
+/// by one costs `stack_need` of those checks; `stack_alloc` does the
+/// bounds check/reallocation once for the whole frame and fills every new
+/// slot with NIL in a single pass. `Heap::stack_alloc` is new surface this
+/// opcode needs from `emulator::heap`, same as `ctx.reductions` is new
+/// surface `consume_reduction` needs from `emulator::runtime_ctx`. Dispatch
+/// tables (`OPCODE_ALLOCATE` -> `opcode_allocate`) live in a per-opcode-
+/// arity jump table built elsewhere in `beam`, not in this file and not
+/// anywhere in this checkout either; wiring a new opcode in means adding a
+/// row there, which has to wait until that table's source is part of the
+/// tree.
+#[inline]
+pub fn opcode_allocate(ctx: &mut Context,
+                       curr_p: &mut Process) -> DispatchResult {
+  assert_arity(gen_op::OPCODE_ALLOCATE, 2);
+
+  let stack_need = ctx.fetch_term().small_get_u();
+  ctx.live = ctx.fetch_term().small_get_u();
+
+  curr_p.heap.stack_alloc(stack_need);
+
+  // Frame setup, not a call/return/BIF application: `consume_reduction`
+  // does not apply here.
+  DispatchResult::Normal
+}
+
+
+/// Same as `opcode_allocate`; BEAM distinguishes `allocate` from
+/// `allocate_zero` only because the latter's frame is guaranteed by the
+/// compiler to need zeroing, which `stack_alloc` already does for every
+/// frame. Structure: allocate_zero(stack_need:int, live:int)
+#[inline]
+pub fn opcode_allocate_zero(ctx: &mut Context,
+                            curr_p: &mut Process) -> DispatchResult {
+  assert_arity(gen_op::OPCODE_ALLOCATE_ZERO, 2);
+  opcode_allocate(ctx, curr_p)
+}
+
+
 /// Jump to the value in `ctx.cp`, set `ctx.cp` to NULL. Empty stack means that
 /// the process has no more code to execute and will end with reason `normal`.
 #[inline]
@@ -130,8 +216,10 @@ pub fn opcode_return(ctx: &mut Context,
 
   if ctx.cp.is_null() {
     if curr_p.heap.stack_depth() == 0 {
-      // Process end of life: return on empty stack
-      panic!("{}Process exit: normal; x0={}", module(), ctx.regs[0])
+      // Process end of life: return on empty stack, normal exit with
+      // whatever is in x0. The scheduler (`emulator::vm`) removes the
+      // process from its run queue on seeing `Finished`.
+      return DispatchResult::Finished(ctx.regs[0])
     } else {
       panic!("{}Return instruction with 0 in ctx.cp", module())
     }
@@ -140,7 +228,7 @@ pub fn opcode_return(ctx: &mut Context,
   ctx.ip = ctx.cp;
   ctx.cp = CodePtr::null();
 
-  DispatchResult::Normal
+  consume_reduction(ctx)
 }
 
 
@@ -26,6 +26,9 @@ mod emulator;
 mod fail;
 mod term;
 
+use std::env;
+use std::path::PathBuf;
+
 use emulator::atom;
 use emulator::scheduler::Prio;
 use emulator::mfa::MFArgs;
@@ -33,6 +36,9 @@ use emulator::vm::VM;
 use term::lterm::*;
 //use term::lterm::list_term;
 
+#[cfg(feature = "disasm")]
+use beam::disasm;
+
 
 /// Entry point for the command-line interface
 fn main() {
@@ -43,6 +49,22 @@ fn main() {
     println!("Erlang Runtime (compat OTP 20)");
   }
 
+  let args: Vec<String> = env::args().collect();
+
+  #[cfg(feature = "disasm")]
+  {
+    if args.len() == 3 && args[1] == "--disasm" {
+      let mut scratch_vm = VM::new();
+      let mut l = beam::loader::Loader::new();
+      let fname = PathBuf::from(&args[2]);
+      l.load(&fname).expect("Failed to load .beam file for disassembly");
+      l.load_stage2(&mut scratch_vm);
+      let modp = l.load_finalize().expect("Failed to finalize loaded module");
+      disasm::disasm_file(&modp);
+      return
+    }
+  }
+
   let mut beam = VM::new();
 
   let mfa = MFArgs::new(